@@ -0,0 +1,104 @@
+use types::{SequenceNumber, ValueType};
+
+use integer_encoding::{FixedInt, VarInt};
+
+/// The key as supplied by the user, with no internal encoding applied.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UserKey<'a>(pub &'a [u8]);
+
+/// `[user_key, tag]` -- the key format used inside SSTables, where `tag` is the 8-byte
+/// `seq << 8 | type` trailer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InternalKey<'a>(pub &'a [u8]);
+
+/// `[keylen, key, tag, vallen, val]` -- the full entry format used as the key inserted into the
+/// memtable's `SkipMap`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MemtableKey<'a>(pub &'a [u8]);
+
+impl<'a> UserKey<'a> {
+    pub fn as_slice(&self) -> &'a [u8] {
+        self.0
+    }
+}
+
+impl<'a> InternalKey<'a> {
+    pub fn as_slice(&self) -> &'a [u8] {
+        self.0
+    }
+}
+
+impl<'a> MemtableKey<'a> {
+    pub fn as_slice(&self) -> &'a [u8] {
+        self.0
+    }
+}
+
+/// Builds a memtable key `[keylen, key, tag, vallen, val]` out of its fields. We are using the
+/// original LevelDB approach here -- encoding key and value into the key that is used for
+/// insertion into the `SkipMap`.
+pub fn build_memtable_key(key: &[u8], value: &[u8], t: ValueType, seq: SequenceNumber) -> Vec<u8> {
+    let mut i = 0;
+    let keysize = key.len();
+    let valsize = value.len();
+
+    let mut buf = Vec::with_capacity(keysize + valsize + keysize.required_space() +
+                                      valsize.required_space() +
+                                      <u64 as FixedInt>::required_space());
+    buf.resize(keysize.required_space(), 0);
+    i += keysize.encode_var(&mut buf[i..]);
+
+    buf.extend(key.iter());
+    i += key.len();
+
+    let flag = (t as u64) | (seq << 8);
+    buf.resize(i + <u64 as FixedInt>::required_space(), 0);
+    flag.encode_fixed(&mut buf[i..]);
+    i += <u64 as FixedInt>::required_space();
+
+    buf.resize(i + valsize.required_space(), 0);
+    i += valsize.encode_var(&mut buf[i..]);
+
+    buf.extend(value.iter());
+    i += value.len();
+
+    assert_eq!(i, buf.len());
+    buf
+}
+
+/// Parses a memtable key into `(keylen, key, tag, vallen, val)`.
+pub fn parse_memtable_key<'a>(mkey: MemtableKey<'a>)
+                               -> (usize, UserKey<'a>, u64, usize, &'a [u8]) {
+    let bytes = mkey.0;
+    let (keylen, mut i): (usize, usize) = VarInt::decode_var(bytes);
+
+    let key = &bytes[i..i + keylen];
+    i += keylen;
+
+    if bytes.len() > i {
+        let tag = FixedInt::decode_fixed(&bytes[i..i + 8]);
+        i += 8;
+
+        let (vallen, j): (usize, usize) = VarInt::decode_var(&bytes[i..]);
+        i += j;
+
+        let val = &bytes[i..];
+
+        (keylen, UserKey(key), tag, vallen, val)
+    } else {
+        (keylen, UserKey(key), 0, 0, &[])
+    }
+}
+
+/// Parses an internal key `[user_key, tag]` into its user key and tag.
+pub fn parse_internal_key<'a>(ikey: InternalKey<'a>) -> (UserKey<'a>, u64) {
+    let bytes = ikey.0;
+    let tag_start = bytes.len() - 8;
+    let tag = FixedInt::decode_fixed(&bytes[tag_start..]);
+    (UserKey(&bytes[..tag_start]), tag)
+}
+
+/// Extracts just the user key portion of a memtable key.
+pub fn extract_user_key<'a>(mkey: MemtableKey<'a>) -> UserKey<'a> {
+    parse_memtable_key(mkey).1
+}
@@ -1,17 +1,20 @@
 use std::cmp::Ordering;
+use std::marker::PhantomData;
 use types::{ValueType, SequenceNumber, Status, LdbIterator};
 use skipmap::{SkipMap, SkipMapIter, Comparator, StandardComparator};
+use key_types::{MemtableKey, UserKey, build_memtable_key, parse_memtable_key};
 
 use integer_encoding::{FixedInt, VarInt};
 
 pub struct LookupKey {
     key: Vec<u8>,
     key_offset: usize,
+    sequence: SequenceNumber,
 }
 
 impl LookupKey {
     #[allow(unused_assignments)]
-    fn new(k: &Vec<u8>, s: SequenceNumber) -> LookupKey {
+    fn new(k: &[u8], s: SequenceNumber) -> LookupKey {
         let mut key = Vec::with_capacity(k.len() + k.len().required_space() +
                                          <u64 as FixedInt>::required_space());
         let mut i = 0;
@@ -22,25 +25,67 @@ impl LookupKey {
         key.extend(k.iter());
         i += k.len();
 
+        // The tag uses the maximum type byte (0xff) rather than a real `ValueType`, so that a
+        // `seek()` to this key lands on the newest entry for `k` with sequence number `<= s`,
+        // regardless of whether that entry is a value or a deletion tombstone.
         key.resize(i + <u64 as FixedInt>::required_space(), 0);
-        (s << 8 | ValueType::TypeValue as u64).encode_fixed(&mut key[i..]);
+        (s << 8 | 0xff).encode_fixed(&mut key[i..]);
         i += <u64 as FixedInt>::required_space();
 
         LookupKey {
             key: key,
             key_offset: k.len().required_space(),
+            sequence: s,
         }
     }
     fn memtable_key<'a>(&'a self) -> &'a Vec<u8> {
         return &self.key;
     }
-    fn user_key(&self) -> Vec<u8> {
-        return self.key[self.key_offset..].to_vec();
+    fn user_key<'a>(&'a self) -> UserKey<'a> {
+        UserKey(&self.key[self.key_offset..self.key.len() - <u64 as FixedInt>::required_space()])
     }
+    fn sequence(&self) -> SequenceNumber {
+        self.sequence
+    }
+}
+
+/// `MemtableKeyComparator` adapts a user-supplied `Comparator` so that it can be used to order
+/// the raw memtable keys stored in the `SkipMap`. Memtable keys are compared by user key first
+/// (using the wrapped comparator), and ties are broken by comparing the 8-byte tag
+/// (`seq << 8 | type`) in *descending* order, so that for a given user key the entry with the
+/// highest sequence number sorts first.
+#[derive(Clone)]
+pub struct MemtableKeyComparator<C: Comparator> {
+    _inner: PhantomData<C>,
 }
 
+impl<C: Comparator> MemtableKeyComparator<C> {
+    fn new() -> MemtableKeyComparator<C> {
+        MemtableKeyComparator { _inner: PhantomData }
+    }
+}
+
+impl<C: Comparator> Comparator for MemtableKeyComparator<C> {
+    fn cmp(a: &[u8], b: &[u8]) -> Ordering {
+        let (_, akey, atag, _, _) = parse_memtable_key(MemtableKey(a));
+        let (_, bkey, btag, _, _) = parse_memtable_key(MemtableKey(b));
+
+        match C::cmp(akey.as_slice(), bkey.as_slice()) {
+            Ordering::Equal => btag.cmp(&atag),
+            ord => ord,
+        }
+    }
+}
+
+/// The default write-buffer threshold, matching LevelDB's `Options::write_buffer_size` default
+/// of 4MiB: once a memtable's approximate memory usage reaches this size, `is_full` reports that
+/// it should be frozen and flushed to an SSTable.
+const DEFAULT_WRITE_BUFFER_SIZE: usize = 4 << 20;
+
 pub struct MemTable<C: Comparator> {
-    map: SkipMap<C>,
+    map: SkipMap<MemtableKeyComparator<C>>,
+    entries: usize,
+    write_buffer_size: usize,
 }
 
 impl MemTable<StandardComparator> {
@@ -50,92 +95,63 @@ impl MemTable<StandardComparator> {
 }
 
 impl<C: Comparator> MemTable<C> {
-    pub fn new_custom_cmp(comparator: C) -> MemTable<C> {
-        MemTable { map: SkipMap::new_with_cmp(comparator) }
+    pub fn new_custom_cmp(_comparator: C) -> MemTable<C> {
+        MemTable {
+            map: SkipMap::new_with_cmp(MemtableKeyComparator::new()),
+            entries: 0,
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+        }
     }
     pub fn approx_mem_usage(&self) -> usize {
         self.map.approx_memory()
     }
 
-    pub fn add(&mut self, seq: SequenceNumber, t: ValueType, key: &Vec<u8>, value: &Vec<u8>) {
-        self.map.insert(Self::build_memtable_key(key, value, t, seq), Vec::new())
+    /// Returns the number of entries (including deletion tombstones) added to this memtable.
+    pub fn len(&self) -> usize {
+        self.entries
     }
 
-    fn build_memtable_key(key: &Vec<u8>,
-                          value: &Vec<u8>,
-                          t: ValueType,
-                          seq: SequenceNumber)
-                          -> Vec<u8> {
-        // We are using the original LevelDB approach here -- encoding key and value into the
-        // key that is used for insertion into the SkipMap.
-        // The format is: [key_size: varint32, key_data: [u8], flags: u64, value_size: varint32,
-        // value_data: [u8]]
-
-        let mut i = 0;
-        let keysize = key.len();
-        let valsize = value.len();
-
-        let mut buf = Vec::with_capacity(keysize + valsize + keysize.required_space() +
-                                         valsize.required_space() +
-                                         <u64 as FixedInt>::required_space());
-        buf.resize(keysize.required_space(), 0);
-        i += keysize.encode_var(&mut buf[i..]);
-
-        buf.extend(key.iter());
-        i += key.len();
-
-        let flag = (t as u64) | (seq << 8);
-        buf.resize(i + <u64 as FixedInt>::required_space(), 0);
-        flag.encode_fixed(&mut buf[i..]);
-        i += <u64 as FixedInt>::required_space();
-
-        buf.resize(i + valsize.required_space(), 0);
-        i += valsize.encode_var(&mut buf[i..]);
-
-        buf.extend(value.iter());
-        i += value.len();
-
-        assert_eq!(i, buf.len());
-        buf
+    /// Sets the write-buffer threshold used by `is_full`.
+    pub fn set_write_buffer_size(&mut self, size: usize) {
+        self.write_buffer_size = size;
     }
 
-    // returns (keylen, key, tag, vallen, val)
-    fn parse_memtable_key(mkey: &Vec<u8>) -> (usize, Vec<u8>, u64, usize, Vec<u8>) {
-        let (keylen, mut i): (usize, usize) = VarInt::decode_var(&mkey);
-
-        let key = mkey[i..i + keylen].to_vec();
-        i += keylen;
-
-        if mkey.len() > i {
-            let tag = FixedInt::decode_fixed(&mkey[i..i + 8]);
-            i += 8;
-
-            let (vallen, j): (usize, usize) = VarInt::decode_var(&mkey[i..]);
-            i += j;
-
-            let val = mkey[i..].to_vec();
+    /// Returns true once `approx_mem_usage` has reached the write-buffer threshold, signaling
+    /// that a higher-level DB loop should freeze this memtable and roll over to a new one.
+    pub fn is_full(&self) -> bool {
+        self.approx_mem_usage() >= self.write_buffer_size
+    }
 
-            return (keylen, key, tag, vallen, val);
-        } else {
-            return (keylen, key, 0, 0, Vec::new());
-        }
+    pub fn add(&mut self, seq: SequenceNumber, t: ValueType, key: &[u8], value: &[u8]) {
+        self.entries += 1;
+        self.map.insert(build_memtable_key(key, value, t, seq), Vec::new())
     }
 
-    #[allow(unused_variables)]
-    pub fn get(&self, key: &LookupKey) -> Result<Vec<u8>, Status> {
+    /// Looks up the value for `key`'s user key as of `key`'s snapshot sequence number.
+    ///
+    /// Returns `Ok(Some(value))` if a live value is found, `Ok(None)` if the most recent entry
+    /// at or before the snapshot is a deletion tombstone (callers searching further down, e.g.
+    /// into SSTables, must stop here rather than treating this as "not present"), and
+    /// `Err(Status::NotFound)` if the user key has no entry in this memtable at or before the
+    /// snapshot (entries written after the snapshot are not visible).
+    pub fn get(&self, key: &LookupKey) -> Result<Option<Vec<u8>>, Status> {
         let mut iter = self.map.iter();
         iter.seek(key.memtable_key());
 
         if iter.valid() {
             let foundkey = iter.current().0;
-            let (lkeylen, lkey, _, _, _) = Self::parse_memtable_key(key.memtable_key());
-            let (fkeylen, fkey, tag, vallen, val) = Self::parse_memtable_key(foundkey);
-
-            if C::cmp(&lkey, &fkey) == Ordering::Equal {
+            let (_, lkey, _, _, _) = parse_memtable_key(MemtableKey(key.memtable_key()));
+            let (_, fkey, tag, _, val) = parse_memtable_key(MemtableKey(foundkey));
+
+            // The seek landed on the newest entry for `fkey` with a tag `<=` the lookup tag; make
+            // sure it's actually for the key we're looking for, and that its sequence number
+            // doesn't postdate the snapshot the caller is reading at.
+            if C::cmp(lkey.as_slice(), fkey.as_slice()) == Ordering::Equal &&
+               (tag >> 8) <= key.sequence() {
                 if tag & 0xff == ValueType::TypeValue as u64 {
-                    return Result::Ok(val);
+                    return Result::Ok(Some(val.to_vec()));
                 } else {
-                    return Result::Err(Status::NotFound(String::new()));
+                    return Result::Ok(None);
                 }
             }
         }
@@ -152,7 +168,7 @@ impl<C: Comparator> MemTable<C> {
 
 pub struct MemtableIterator<'a, C: 'a + Comparator> {
     _tbl: &'a MemTable<C>,
-    skipmapiter: SkipMapIter<'a, C>,
+    skipmapiter: SkipMapIter<'a, MemtableKeyComparator<C>>,
 }
 
 impl<'a, C: 'a + Comparator> Iterator for MemtableIterator<'a, C> {
@@ -161,10 +177,10 @@ impl<'a, C: 'a + Comparator> Iterator for MemtableIterator<'a, C> {
     fn next(&mut self) -> Option<Self::Item> {
         loop {
             if let Some((foundkey, _)) = self.skipmapiter.next() {
-                let (_, key, tag, _, val) = MemTable::<C>::parse_memtable_key(foundkey);
+                let (_, key, tag, _, val) = parse_memtable_key(MemtableKey(foundkey));
 
                 if tag & 0xff == ValueType::TypeValue as u64 {
-                    return Some((key, val));
+                    return Some((key.as_slice().to_vec(), val.to_vec()));
                 } else {
                     continue;
                 }
@@ -183,16 +199,19 @@ impl<'a, C: 'a + Comparator> LdbIterator<'a> for MemtableIterator<'a, C> {
         assert!(self.valid());
 
         let (foundkey, _) = self.skipmapiter.current();
-        let (_, key, tag, _, val) = MemTable::<C>::parse_memtable_key(foundkey);
+        let (_, key, tag, _, val) = parse_memtable_key(MemtableKey(foundkey));
 
         if tag & 0xff == ValueType::TypeValue as u64 {
-            return (key, val);
+            return (key.as_slice().to_vec(), val.to_vec());
         } else {
             panic!("should not happen");
         }
     }
-    fn seek(&mut self, to: &Vec<u8>) {
-        self.skipmapiter.seek(LookupKey::new(to, 0).memtable_key());
+    fn seek(&mut self, to: &[u8]) {
+        // Probe with the maximum sequence number so the seek lands on the newest entry for
+        // `to` (or on the next user key if `to` has none), rather than sorting after every real version of that key -- ties are
+        // broken by *descending* tag, so a low-sequence probe would otherwise be skipped past.
+        self.skipmapiter.seek(LookupKey::new(to, SequenceNumber::max_value()).memtable_key());
     }
 }
 
@@ -201,6 +220,7 @@ mod tests {
     use super::*;
     use types::*;
     use skipmap::StandardComparator;
+    use key_types::{MemtableKey, parse_memtable_key};
 
     fn get_memtable() -> MemTable<StandardComparator> {
         let mut mt = MemTable::new();
@@ -210,10 +230,7 @@ mod tests {
                            (123, "abf", "126")];
 
         for e in entries.iter() {
-            mt.add(e.0,
-                   ValueType::TypeValue,
-                   &e.1.as_bytes().to_vec(),
-                   &e.2.as_bytes().to_vec());
+            mt.add(e.0, ValueType::TypeValue, e.1.as_bytes(), e.2.as_bytes());
         }
         mt
     }
@@ -221,10 +238,7 @@ mod tests {
     #[test]
     fn test_add() {
         let mut mt = MemTable::new();
-        mt.add(123,
-               ValueType::TypeValue,
-               &"abc".as_bytes().to_vec(),
-               &"123".as_bytes().to_vec());
+        mt.add(123, ValueType::TypeValue, "abc".as_bytes(), "123".as_bytes());
 
         assert_eq!(mt.map.iter().next().unwrap().0,
                    &vec![3, 97, 98, 99, 1, 123, 0, 0, 0, 0, 0, 0, 3, 49, 50, 51]);
@@ -234,23 +248,66 @@ mod tests {
     fn test_add_get() {
         let mt = get_memtable();
 
-        if let Result::Ok(v) = mt.get(&LookupKey::new(&"abc".as_bytes().to_vec(), 120)) {
+        if let Result::Ok(Some(v)) = mt.get(&LookupKey::new("abc".as_bytes(), 120)) {
             assert_eq!(v, "123".as_bytes().to_vec());
         } else {
             panic!("not found");
         }
 
-        if let Result::Ok(v) = mt.get(&LookupKey::new(&"abe".as_bytes().to_vec(), 122)) {
+        if let Result::Ok(Some(v)) = mt.get(&LookupKey::new("abe".as_bytes(), 122)) {
             assert_eq!(v, "125".as_bytes().to_vec());
         } else {
             panic!("not found");
         }
 
-        if let Result::Ok(v) = mt.get(&LookupKey::new(&"abc".as_bytes().to_vec(), 124)) {
+        // "abc" was inserted at seq 120, so a later snapshot still sees it...
+        if let Result::Ok(Some(v)) = mt.get(&LookupKey::new("abc".as_bytes(), 124)) {
+            assert_eq!(v, "123".as_bytes().to_vec());
+        } else {
+            panic!("not found");
+        }
+
+        // ...but a snapshot taken before it was written must not.
+        if let Result::Err(Status::NotFound(_)) = mt.get(&LookupKey::new("abc".as_bytes(), 119)) {
+        } else {
             panic!("found");
         }
     }
 
+    #[test]
+    fn test_delete() {
+        let mut mt = get_memtable();
+        mt.add(125, ValueType::TypeDeletion, "abc".as_bytes(), &[]);
+
+        // The tombstone shadows the earlier value for snapshots taken after the deletion...
+        match mt.get(&LookupKey::new("abc".as_bytes(), 125)) {
+            Result::Ok(None) => (),
+            _ => panic!("expected tombstone"),
+        }
+
+        // ...but a snapshot taken before the deletion still sees the live value.
+        if let Result::Ok(Some(v)) = mt.get(&LookupKey::new("abc".as_bytes(), 120)) {
+            assert_eq!(v, "123".as_bytes().to_vec());
+        } else {
+            panic!("not found");
+        }
+
+        // A tombstone for a key that was never otherwise inserted is still a tombstone, not
+        // "not found" -- callers must stop searching lower levels either way.
+        mt.add(125, ValueType::TypeDeletion, "nonexistent".as_bytes(), &[]);
+        match mt.get(&LookupKey::new("nonexistent".as_bytes(), 125)) {
+            Result::Ok(None) => (),
+            _ => panic!("expected tombstone"),
+        }
+
+        // A key that was never added at all, and never deleted, is indistinguishable from any
+        // other absence.
+        match mt.get(&LookupKey::new("neveradded".as_bytes(), 125)) {
+            Result::Err(Status::NotFound(_)) => (),
+            _ => panic!("expected not found"),
+        }
+    }
+
     #[test]
     fn test_memtable_iterator() {
         let mt = get_memtable();
@@ -263,20 +320,52 @@ mod tests {
         assert_eq!(iter.current().0, vec![97, 98, 99]);
         assert_eq!(iter.current().1, vec![49, 50, 51]);
 
-        iter.seek(&"abf".as_bytes().to_vec());
+        iter.seek("abf".as_bytes());
         assert_eq!(iter.current().0, vec![97, 98, 102]);
         assert_eq!(iter.current().1, vec![49, 50, 54]);
     }
 
+    #[test]
+    fn test_memtable_ordering_by_seq_desc() {
+        let mut mt = MemTable::new();
+        // Three versions of the same user key, inserted out of sequence order.
+        mt.add(120, ValueType::TypeValue, "abc".as_bytes(), "first".as_bytes());
+        mt.add(122, ValueType::TypeValue, "abc".as_bytes(), "third".as_bytes());
+        mt.add(121, ValueType::TypeValue, "abc".as_bytes(), "second".as_bytes());
+
+        let mut iter = mt.iter();
+        iter.next();
+        assert_eq!(iter.current().1, "third".as_bytes().to_vec());
+        iter.next();
+        assert_eq!(iter.current().1, "second".as_bytes().to_vec());
+        iter.next();
+        assert_eq!(iter.current().1, "first".as_bytes().to_vec());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_len() {
+        let mt = get_memtable();
+        assert_eq!(mt.len(), 4);
+    }
+
+    #[test]
+    fn test_is_full() {
+        let mut mt = get_memtable();
+        assert!(!mt.is_full());
+
+        mt.set_write_buffer_size(1);
+        assert!(mt.is_full());
+    }
+
     #[test]
     fn test_parse_memtable_key() {
         let key = vec![3, 1, 2, 3, 1, 123, 0, 0, 0, 0, 0, 0, 3, 4, 5, 6];
-        let (keylen, key, tag, vallen, val) =
-            MemTable::<StandardComparator>::parse_memtable_key(&key);
+        let (keylen, key, tag, vallen, val) = parse_memtable_key(MemtableKey(&key));
         assert_eq!(keylen, 3);
-        assert_eq!(key, vec![1, 2, 3]);
+        assert_eq!(key.as_slice(), &[1, 2, 3]);
         assert_eq!(tag, 123 << 8 | 1);
         assert_eq!(vallen, 3);
-        assert_eq!(val, vec![4, 5, 6]);
+        assert_eq!(val, &[4, 5, 6]);
     }
-}
\ No newline at end of file
+}